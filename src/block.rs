@@ -0,0 +1,37 @@
+use core::ops::Deref;
+use stm32f1xx_hal::pac;
+
+use crate::sd::{SdCardError, SdCardStatus, SpiSdCard};
+
+/// A device addressable as a sequence of fixed-size 512-byte blocks, in the
+/// spirit of the block/controller split used by embedded-sdmmc. `SpiSdCard`
+/// is the only implementor for now, but this lets the FAT layer in `fat.rs`
+/// stay agnostic of the underlying transport.
+pub trait BlockDevice {
+    type Error;
+
+    fn read_block(&mut self, lba: u32, buf: &mut [u8; 512]) -> Result<(), Self::Error>;
+    fn write_block(&mut self, lba: u32, buf: &[u8; 512]) -> Result<(), Self::Error>;
+    fn num_blocks(&mut self) -> Result<u64, Self::Error>;
+}
+
+impl<SPI: Deref<Target = pac::spi1::RegisterBlock>, REMAP, PINS> BlockDevice
+    for SpiSdCard<SPI, REMAP, PINS>
+{
+    type Error = SdCardError;
+
+    fn read_block(&mut self, lba: u32, buf: &mut [u8; 512]) -> Result<(), SdCardError> {
+        self.read_sector(lba, buf)
+    }
+
+    fn write_block(&mut self, lba: u32, buf: &[u8; 512]) -> Result<(), SdCardError> {
+        self.write_sector(lba, buf)
+    }
+
+    fn num_blocks(&mut self) -> Result<u64, SdCardError> {
+        match self.status() {
+            SdCardStatus::Ready(cap) => Ok(cap),
+            _ => Err(SdCardError::InvalidResponse),
+        }
+    }
+}
@@ -0,0 +1,38 @@
+//! CRC7 (command) and CRC16-CCITT (data block) checksums used by the SD card
+//! SPI protocol.
+
+const CRC7_POLY: u8 = 0x09;
+const CRC16_POLY: u16 = 0x1021;
+
+/// Computes the CRC7 used on the command line: polynomial x^7 + x^3 + 1,
+/// MSB-first over `bytes`, returned with the stop bit set in the low bit.
+pub fn crc7(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in bytes {
+        let mut d = byte;
+        for _ in 0..8 {
+            crc <<= 1;
+            if (d ^ crc) & 0x80 != 0 {
+                crc ^= CRC7_POLY;
+            }
+            d <<= 1;
+        }
+    }
+    (crc << 1) | 1
+}
+
+/// Computes the CRC16-CCITT used to protect a 512-byte data block.
+pub fn crc16_ccitt(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ CRC16_POLY;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
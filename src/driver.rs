@@ -1,3 +1,4 @@
+use crate::fat::{DirEntry, Fat, FatError};
 use crate::{SdCardStatus, SpiSdCard, sd::SdCardError};
 use core::ops::Deref;
 use stm32f1xx_hal::pac::spi1::RegisterBlock as SpiRegs;
@@ -8,12 +9,25 @@ use usbd_serial::SerialPort;
 const CMD_STATUS: u8 = 0x02;
 const CMD_READ: u8 = 0x03;
 const CMD_WRITE: u8 = 0x04;
+const CMD_LIST_DIR: u8 = 0x05;
+const CMD_READ_FILE: u8 = 0x06;
 const CMD_END: u8 = 0xF3;
 
 const CMD_STATUS_GENERAL: u8 = 0x00;
 const CMD_STATUS_INIT: u8 = 0x01;
 
-static mut WRITEBUF: [u8; 512] = [0; 512];
+// Largest sector count a single CMD_READ/CMD_WRITE may request, bounded by
+// the size of the static staging buffers below.
+const MAX_SECTORS: usize = 8;
+
+// Largest file name CMD_READ_FILE can carry, bounded by cmd_buffer's size.
+const MAX_NAME_LEN: usize = 31;
+
+// Largest number of directory entries a single CMD_LIST_DIR reports.
+const MAX_DIR_ENTRIES: usize = 15;
+
+static mut WRITEBUF: [u8; 512 * MAX_SECTORS] = [0; 512 * MAX_SECTORS];
+static mut READBUF: [u8; 512 * MAX_SECTORS] = [0; 512 * MAX_SECTORS];
 
 #[derive(Copy, Clone, Debug)]
 #[repr(u8)]
@@ -23,17 +37,22 @@ enum Status {
     SdFault = 0x81,
     SdTimeout = 0x82,
     SdNotReady = 0x83,
-    SdTransportFault = 0x84
+    SdTransportFault = 0x84,
+    SdCrcMismatch = 0x85,
+    FatFault = 0x86
 }
 
 enum Command {
     Status(u8),
-    Read(u64),
-    Write(u64),
+    Read(u64, u16),
+    Write(u64, u16),
+    ListDir,
+    ReadFile([u8; MAX_NAME_LEN], usize),
 }
 
 struct PendingWrite {
     lba: u64,
+    count: u16,
     off: usize,
 }
 
@@ -46,6 +65,7 @@ pub struct Driver<'a, SPI: Deref<Target = SpiRegs>, REMAP, PINS, B: bus::UsbBus>
     cmd_len: usize,
 
     write: Option<PendingWrite>,
+    card_was_present: bool,
 }
 
 trait Writer {
@@ -80,12 +100,26 @@ fn read_dword_le(b: &[u8]) -> u64 {
         | ((b[7] as u64) << 56)
 }
 
+fn read_word_le(b: &[u8]) -> u16 {
+    (b[0] as u16) | ((b[1] as u16) << 8)
+}
+
 impl From<SdCardError> for Status {
     fn from(t: SdCardError) -> Self {
         match t {
             SdCardError::SpiError => Status::SdTransportFault,
             SdCardError::Timeout => Status::SdTimeout,
-            SdCardError::InvalidResponse => Status::SdFault
+            SdCardError::InvalidResponse => Status::SdFault,
+            SdCardError::CrcMismatch => Status::SdCrcMismatch
+        }
+    }
+}
+
+impl<E: Into<Status>> From<FatError<E>> for Status {
+    fn from(e: FatError<E>) -> Self {
+        match e {
+            FatError::Block(e) => e.into(),
+            _ => Status::FatFault
         }
     }
 }
@@ -106,20 +140,33 @@ impl TryFrom<&[u8]> for Command {
                 Ok(Command::Status(bytes[1]))
             }
             CMD_READ => {
-                if bytes.len() != 10 {
+                if bytes.len() != 11 {
                     return Err(());
                 }
-                Ok(Command::Read(read_dword_le(&bytes[1..])))
+                Ok(Command::Read(read_dword_le(&bytes[1..9]), read_word_le(&bytes[9..11])))
             }
             CMD_WRITE => {
-                if bytes.len() != 10 {
+                if bytes.len() != 11 {
                     return Err(());
                 }
-                Ok(Command::Write(read_dword_le(&bytes[1..])))
+                Ok(Command::Write(read_dword_le(&bytes[1..9]), read_word_le(&bytes[9..11])))
             }
-            _ => {
-                todo!()
+            CMD_LIST_DIR => {
+                if bytes.len() != 1 {
+                    return Err(());
+                }
+                Ok(Command::ListDir)
+            }
+            CMD_READ_FILE => {
+                let name_len = bytes.len() - 1;
+                if name_len == 0 || name_len > MAX_NAME_LEN {
+                    return Err(());
+                }
+                let mut name = [0u8; MAX_NAME_LEN];
+                name[..name_len].copy_from_slice(&bytes[1..]);
+                Ok(Command::ReadFile(name, name_len))
             }
+            _ => Err(()),
         }
     }
 }
@@ -145,6 +192,7 @@ impl<'a, SPI: Deref<Target = SpiRegs>, R, P, B: bus::UsbBus> Driver<'a, SPI, R,
             cmd_len: 0,
 
             write: None,
+            card_was_present: true,
         }
     }
 
@@ -180,16 +228,24 @@ impl<'a, SPI: Deref<Target = SpiRegs>, R, P, B: bus::UsbBus> Driver<'a, SPI, R,
         self.cdc.flush().ok();
     }
 
-    fn handle_read(&mut self, lba: u64) {
+    fn handle_read(&mut self, lba: u64, count: u16) {
         // Send sector data
 
-        let mut buf = [0u8; 512];
-        match self.sd.read_sector(lba as u32, &mut buf) {
+        if count == 0 || count as usize > MAX_SECTORS {
+            self.send_byte(1);
+            self.send_byte(Status::SerialFault as u8);
+            self.cdc.flush().ok();
+            return;
+        }
+
+        let len = count as usize * 512;
+        let buf = unsafe { &mut READBUF[0..len] };
+        match self.sd.read_sectors(lba as u32, count as usize, buf) {
             Ok(_) => {
                 self.send_byte(1);
                 self.send_byte(Status::Ok as u8);
 
-                for &byte in &buf {
+                for &byte in buf.iter() {
                     self.send_byte(byte);
                 }
             }
@@ -202,10 +258,17 @@ impl<'a, SPI: Deref<Target = SpiRegs>, R, P, B: bus::UsbBus> Driver<'a, SPI, R,
         self.cdc.flush().ok();
     }
 
-    fn handle_write_begin(&mut self, lba: u64) {
+    fn handle_write_begin(&mut self, lba: u64, count: u16) {
+        if count == 0 || count as usize > MAX_SECTORS {
+            self.send_byte(1);
+            self.send_byte(Status::SerialFault as u8);
+            self.cdc.flush().ok();
+            return;
+        }
+
         match self.sd.status() {
             SdCardStatus::Ready(_) => {
-                self.write = Some(PendingWrite { lba, off: 0 });
+                self.write = Some(PendingWrite { lba, count, off: 0 });
                 self.send_byte(1);
                 self.send_byte(Status::Ok as u8);
                 self.cdc.flush().ok();
@@ -220,14 +283,15 @@ impl<'a, SPI: Deref<Target = SpiRegs>, R, P, B: bus::UsbBus> Driver<'a, SPI, R,
 
     fn handle_write_byte(&mut self, b: u8) {
         if let Some(ref mut write) = self.write {
-            assert!(write.off != 512);
+            let len = write.count as usize * 512;
+            assert!(write.off != len);
 
             unsafe {
                 WRITEBUF[write.off] = b;
             }
             write.off += 1;
 
-            if write.off == 512 {
+            if write.off == len {
                 // Acknowledge write
                 self.handle_write_done();
             }
@@ -235,8 +299,9 @@ impl<'a, SPI: Deref<Target = SpiRegs>, R, P, B: bus::UsbBus> Driver<'a, SPI, R,
     }
 
     fn handle_write_done(&mut self) {
-        if let Some(ref mut write) = self.write {
-            let status = match self.sd.write_sector(write.lba as u32, unsafe { &WRITEBUF }) {
+        if let Some(ref write) = self.write {
+            let len = write.count as usize * 512;
+            let status = match self.sd.write_sectors(write.lba as u32, unsafe { &WRITEBUF[0..len] }) {
                 Ok(_) => Status::Ok,
                 Err(e) => e.into(),
             };
@@ -251,15 +316,129 @@ impl<'a, SPI: Deref<Target = SpiRegs>, R, P, B: bus::UsbBus> Driver<'a, SPI, R,
         self.write = None;
     }
 
+    fn handle_list_dir(&mut self) {
+        let mut fat = match Fat::mount(&mut self.sd) {
+            Ok(fat) => fat,
+            Err(e) => {
+                self.send_byte(1);
+                self.send_byte(Status::from(e) as u8);
+                self.cdc.flush().ok();
+                return;
+            }
+        };
+
+        let mut entries: [Option<DirEntry>; MAX_DIR_ENTRIES] = [None; MAX_DIR_ENTRIES];
+        let mut count = 0usize;
+        let res = fat.read_root_dir(|entry| {
+            if count < MAX_DIR_ENTRIES {
+                entries[count] = Some(*entry);
+                count += 1;
+            }
+        });
+
+        match res {
+            Ok(_) => {
+                self.send_byte(1);
+                self.send_byte(Status::Ok as u8);
+                self.send_byte(count as u8);
+
+                for entry in entries[..count].iter().filter_map(|e| e.as_ref()) {
+                    for &b in &entry.name {
+                        self.send_byte(b);
+                    }
+                    self.send_byte(entry.is_dir as u8);
+                    self.send_word_le(entry.size);
+                }
+            }
+            Err(e) => {
+                self.send_byte(1);
+                self.send_byte(Status::from(e) as u8);
+            }
+        }
+
+        self.cdc.flush().ok();
+    }
+
+    fn handle_read_file(&mut self, name: &[u8]) {
+        let name = match core::str::from_utf8(name) {
+            Ok(name) => name,
+            Err(_) => {
+                self.send_byte(1);
+                self.send_byte(Status::SerialFault as u8);
+                self.cdc.flush().ok();
+                return;
+            }
+        };
+
+        let mut fat = match Fat::mount(&mut self.sd) {
+            Ok(fat) => fat,
+            Err(e) => {
+                self.send_byte(1);
+                self.send_byte(Status::from(e) as u8);
+                self.cdc.flush().ok();
+                return;
+            }
+        };
+
+        let entry = match fat.find(name) {
+            Ok(entry) => entry,
+            Err(e) => {
+                self.send_byte(1);
+                self.send_byte(Status::from(e) as u8);
+                self.cdc.flush().ok();
+                return;
+            }
+        };
+
+        self.send_byte(1);
+        self.send_byte(Status::Ok as u8);
+        self.send_word_le(entry.size);
+
+        let mut offset = 0u32;
+        let mut buf = [0u8; 512];
+        while offset < entry.size {
+            let n = match fat.read_file(&entry, offset, &mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+
+            for &b in &buf[..n] {
+                self.send_byte(b);
+            }
+            offset += n as u32;
+        }
+
+        self.cdc.flush().ok();
+    }
+
     fn handle_command(&mut self, cmd: Command) {
         match cmd {
             Command::Status(mode) => self.handle_status(mode),
-            Command::Read(lba) => self.handle_read(lba),
-            Command::Write(lba) => self.handle_write_begin(lba),
+            Command::Read(lba, count) => self.handle_read(lba, count),
+            Command::Write(lba, count) => self.handle_write_begin(lba, count),
+            Command::ListDir => self.handle_list_dir(),
+            Command::ReadFile(name, len) => self.handle_read_file(&name[..len]),
+        }
+    }
+
+    fn poll_card_detect(&mut self) {
+        let present = self.sd.card_present();
+        if present == self.card_was_present {
+            return;
+        }
+        self.card_was_present = present;
+
+        if present {
+            self.sd.init().ok();
+        } else {
+            self.sd.mark_absent();
+            self.write = None;
         }
     }
 
     pub fn poll(&mut self) {
+        self.poll_card_detect();
+
         let mut buf = [0u8; 16];
 
         if !self.dev.poll(&mut [&mut self.cdc]) {
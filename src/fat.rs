@@ -0,0 +1,316 @@
+use crate::block::BlockDevice;
+
+#[derive(Debug)]
+pub enum FatError<E> {
+    Block(E),
+    /// The MBR has no FAT-looking partition in its first slot.
+    NoPartition,
+    /// The BPB in the partition's boot sector doesn't look like FAT16/FAT32.
+    NotFat,
+    NotFound,
+    NotAFile,
+}
+
+impl<E> From<E> for FatError<E> {
+    fn from(e: E) -> Self {
+        FatError::Block(e)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum FatType {
+    Fat16,
+    Fat32,
+}
+
+/// One 8.3 directory entry, as found by `Fat::read_root_dir`/`Fat::find`.
+#[derive(Copy, Clone)]
+pub struct DirEntry {
+    /// Raw 8.3 name: 8 bytes of name, 3 bytes of extension, space-padded.
+    pub name: [u8; 11],
+    pub is_dir: bool,
+    pub size: u32,
+    first_cluster: u32,
+}
+
+impl DirEntry {
+    fn parse(raw: &[u8]) -> Option<Self> {
+        let first_byte = raw[0];
+        if first_byte == 0x00 || first_byte == 0xE5 {
+            return None;
+        }
+        let attr = raw[11];
+        if attr & 0x08 != 0 || attr & 0x0F == 0x0F {
+            // Volume label or long-file-name entry; not handled here.
+            return None;
+        }
+
+        let mut name = [0u8; 11];
+        name.copy_from_slice(&raw[0..11]);
+
+        let cluster_hi = u16::from_le_bytes([raw[20], raw[21]]) as u32;
+        let cluster_lo = u16::from_le_bytes([raw[26], raw[27]]) as u32;
+        let size = u32::from_le_bytes([raw[28], raw[29], raw[30], raw[31]]);
+
+        Some(Self {
+            name,
+            is_dir: attr & 0x10 != 0,
+            size,
+            first_cluster: (cluster_hi << 16) | cluster_lo,
+        })
+    }
+}
+
+fn to_83_name(name: &str) -> [u8; 11] {
+    let mut out = [b' '; 11];
+    let mut parts = name.splitn(2, '.');
+    let base = parts.next().unwrap_or("");
+    let ext = parts.next().unwrap_or("");
+
+    for (i, b) in base.bytes().take(8).enumerate() {
+        out[i] = b.to_ascii_uppercase();
+    }
+    for (i, b) in ext.bytes().take(3).enumerate() {
+        out[8 + i] = b.to_ascii_uppercase();
+    }
+
+    out
+}
+
+/// A minimal read-only FAT16/FAT32 layer: finds the first MBR partition,
+/// parses its BPB, and can enumerate the root directory and read files by
+/// walking their cluster chains. No subdirectory traversal or write support.
+pub struct Fat<'a, D: BlockDevice> {
+    dev: &'a mut D,
+    partition_lba: u32,
+    fat_type: FatType,
+    bytes_per_sector: u16,
+    sectors_per_cluster: u8,
+    reserved_sectors: u16,
+    num_fats: u8,
+    fat_size: u32,
+    root_entry_count: u16,
+    first_data_sector: u32,
+    root_cluster: u32,
+}
+
+impl<'a, D: BlockDevice> Fat<'a, D> {
+    pub fn mount(dev: &'a mut D) -> Result<Self, FatError<D::Error>> {
+        let mut sector = [0u8; 512];
+
+        dev.read_block(0, &mut sector)?;
+        if sector[510] != 0x55 || sector[511] != 0xAA {
+            return Err(FatError::NoPartition);
+        }
+
+        let entry = &sector[446..462];
+        let partition_type = entry[4];
+        let partition_lba = u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]);
+
+        if partition_lba == 0
+            || !matches!(partition_type, 0x01 | 0x04 | 0x06 | 0x0B | 0x0C | 0x0E)
+        {
+            return Err(FatError::NoPartition);
+        }
+
+        dev.read_block(partition_lba, &mut sector)?;
+        if sector[510] != 0x55 || sector[511] != 0xAA {
+            return Err(FatError::NotFat);
+        }
+
+        let bytes_per_sector = u16::from_le_bytes([sector[11], sector[12]]);
+        let sectors_per_cluster = sector[13];
+        let reserved_sectors = u16::from_le_bytes([sector[14], sector[15]]);
+        let num_fats = sector[16];
+        let root_entry_count = u16::from_le_bytes([sector[17], sector[18]]);
+        let fat_size_16 = u16::from_le_bytes([sector[22], sector[23]]) as u32;
+        let fat_size_32 = u32::from_le_bytes([sector[36], sector[37], sector[38], sector[39]]);
+
+        if bytes_per_sector as usize != 512 || sectors_per_cluster == 0 || num_fats == 0 {
+            return Err(FatError::NotFat);
+        }
+
+        let fat_type = if root_entry_count == 0 {
+            FatType::Fat32
+        } else {
+            FatType::Fat16
+        };
+        let fat_size = if fat_size_16 != 0 { fat_size_16 } else { fat_size_32 };
+        let root_cluster = if fat_type == FatType::Fat32 {
+            u32::from_le_bytes([sector[44], sector[45], sector[46], sector[47]])
+        } else {
+            0
+        };
+
+        let root_dir_sectors =
+            ((root_entry_count as u32 * 32) + (bytes_per_sector as u32 - 1)) / bytes_per_sector as u32;
+        let first_data_sector =
+            reserved_sectors as u32 + num_fats as u32 * fat_size + root_dir_sectors;
+
+        Ok(Self {
+            dev,
+            partition_lba,
+            fat_type,
+            bytes_per_sector,
+            sectors_per_cluster,
+            reserved_sectors,
+            num_fats,
+            fat_size,
+            root_entry_count,
+            first_data_sector,
+            root_cluster,
+        })
+    }
+
+    fn cluster_to_sector(&self, cluster: u32) -> u32 {
+        self.partition_lba + self.first_data_sector + (cluster - 2) * self.sectors_per_cluster as u32
+    }
+
+    /// Reads the FAT table entry for `cluster`, returning the next cluster
+    /// in the chain, or a value `>= 0x0FFFFFF8` (FAT32) / `>= 0xFFF8` (FAT16)
+    /// at end-of-chain.
+    fn next_cluster(&mut self, cluster: u32) -> Result<u32, FatError<D::Error>> {
+        let mut sector = [0u8; 512];
+
+        match self.fat_type {
+            FatType::Fat16 => {
+                let fat_offset = cluster * 2;
+                let fat_sector = self.partition_lba
+                    + self.reserved_sectors as u32
+                    + fat_offset / self.bytes_per_sector as u32;
+                let off = (fat_offset % self.bytes_per_sector as u32) as usize;
+
+                self.dev.read_block(fat_sector, &mut sector)?;
+                Ok(u16::from_le_bytes([sector[off], sector[off + 1]]) as u32)
+            }
+            FatType::Fat32 => {
+                let fat_offset = cluster * 4;
+                let fat_sector = self.partition_lba
+                    + self.reserved_sectors as u32
+                    + fat_offset / self.bytes_per_sector as u32;
+                let off = (fat_offset % self.bytes_per_sector as u32) as usize;
+
+                self.dev.read_block(fat_sector, &mut sector)?;
+                let raw = u32::from_le_bytes([
+                    sector[off],
+                    sector[off + 1],
+                    sector[off + 2],
+                    sector[off + 3],
+                ]);
+                Ok(raw & 0x0FFF_FFFF)
+            }
+        }
+    }
+
+    fn is_eoc(&self, cluster: u32) -> bool {
+        match self.fat_type {
+            FatType::Fat16 => cluster >= 0xFFF8,
+            FatType::Fat32 => cluster >= 0x0FFF_FFF8,
+        }
+    }
+
+    /// Walks the root directory, calling `visit` with each 8.3 entry in turn.
+    pub fn read_root_dir<F: FnMut(&DirEntry)>(&mut self, mut visit: F) -> Result<(), FatError<D::Error>> {
+        let mut sector = [0u8; 512];
+
+        match self.fat_type {
+            FatType::Fat16 => {
+                let root_dir_sector =
+                    self.partition_lba + self.reserved_sectors as u32 + self.num_fats as u32 * self.fat_size;
+                let root_dir_sectors = ((self.root_entry_count as u32 * 32)
+                    + (self.bytes_per_sector as u32 - 1))
+                    / self.bytes_per_sector as u32;
+
+                for i in 0..root_dir_sectors {
+                    self.dev.read_block(root_dir_sector + i, &mut sector)?;
+                    for raw in sector.chunks(32) {
+                        if let Some(entry) = DirEntry::parse(raw) {
+                            visit(&entry);
+                        }
+                    }
+                }
+            }
+            FatType::Fat32 => {
+                let mut cluster = self.root_cluster;
+                while !self.is_eoc(cluster) {
+                    let start = self.cluster_to_sector(cluster);
+                    for i in 0..self.sectors_per_cluster as u32 {
+                        self.dev.read_block(start + i, &mut sector)?;
+                        for raw in sector.chunks(32) {
+                            if let Some(entry) = DirEntry::parse(raw) {
+                                visit(&entry);
+                            }
+                        }
+                    }
+                    cluster = self.next_cluster(cluster)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Finds an entry in the root directory by its (case-insensitive) 8.3
+    /// name, e.g. `"README.TXT"`.
+    pub fn find(&mut self, name: &str) -> Result<DirEntry, FatError<D::Error>> {
+        let wanted = to_83_name(name);
+        let mut found = None;
+
+        self.read_root_dir(|entry| {
+            if found.is_none() && entry.name == wanted {
+                found = Some(*entry);
+            }
+        })?;
+
+        found.ok_or(FatError::NotFound)
+    }
+
+    /// Reads up to `buf.len()` bytes starting at `offset` into the file
+    /// described by `entry`, returning the number of bytes actually read.
+    pub fn read_file(
+        &mut self,
+        entry: &DirEntry,
+        offset: u32,
+        buf: &mut [u8],
+    ) -> Result<usize, FatError<D::Error>> {
+        if entry.is_dir {
+            return Err(FatError::NotAFile);
+        }
+        if offset >= entry.size {
+            return Ok(0);
+        }
+
+        let cluster_bytes = self.sectors_per_cluster as u32 * self.bytes_per_sector as u32;
+        let mut cluster = entry.first_cluster;
+        let mut skip_clusters = offset / cluster_bytes;
+        while skip_clusters > 0 {
+            cluster = self.next_cluster(cluster)?;
+            skip_clusters -= 1;
+        }
+
+        let to_read = buf.len().min((entry.size - offset) as usize);
+        let mut done = 0;
+        let mut pos_in_cluster = offset % cluster_bytes;
+        let mut sector = [0u8; 512];
+
+        while done < to_read {
+            let sector_in_cluster = pos_in_cluster / self.bytes_per_sector as u32;
+            let off_in_sector = (pos_in_cluster % self.bytes_per_sector as u32) as usize;
+
+            self.dev
+                .read_block(self.cluster_to_sector(cluster) + sector_in_cluster, &mut sector)?;
+
+            let chunk = (512 - off_in_sector).min(to_read - done);
+            buf[done..done + chunk].copy_from_slice(&sector[off_in_sector..off_in_sector + chunk]);
+            done += chunk;
+            pos_in_cluster += chunk as u32;
+
+            if pos_in_cluster >= cluster_bytes {
+                pos_in_cluster = 0;
+                cluster = self.next_cluster(cluster)?;
+            }
+        }
+
+        Ok(done)
+    }
+}
@@ -15,12 +15,18 @@ use stm32f1xx_hal::{
     spi::{Mode, Phase, Polarity, Spi},
 };
 use usb_device::prelude::*;
-use usbd_serial::{SerialPort, USB_CLASS_CDC};
 
+mod crc;
 mod sd;
 pub use sd::{SdCardStatus, SpiSdCard};
+mod block;
+mod fat;
+// Bespoke CDC framed-command protocol; kept for hosts still using the old
+// tool, but the board now boots into the MSC driver below by default.
+#[allow(dead_code)]
 mod driver;
-use driver::Driver;
+mod msc;
+use msc::{MscClass, MscDriver};
 
 #[entry]
 fn main() -> ! {
@@ -52,6 +58,7 @@ fn main() -> ! {
         gpiob.pb15.into_alternate_push_pull(&mut gpiob.crh),
     );
     let spi_cs = gpiob.pb12.into_push_pull_output(&mut gpiob.crh);
+    let card_detect = gpiob.pb5.into_pull_up_input(&mut gpiob.crl);
     let spi_mode = Mode {
         polarity: Polarity::IdleLow,
         phase: Phase::CaptureOnFirstTransition,
@@ -64,7 +71,7 @@ fn main() -> ! {
         clocks,
         &mut rcc.apb1,
     );
-    let mut sd = SpiSdCard::new(spi, spi_cs);
+    let mut sd = SpiSdCard::new(spi, spi_cs, Some(card_detect));
 
     // LED
     let mut led = gpioc.pc13.into_push_pull_output(&mut gpioc.crh);
@@ -81,13 +88,14 @@ fn main() -> ! {
     };
     let usb_bus = UsbBus::new(usb);
 
-    let usb_serial = SerialPort::new(&usb_bus);
+    let usb_msc = MscClass::new(&usb_bus);
 
+    // Class is declared on the MSC interface itself (see MscClass), so the
+    // device class stays at 0x00.
     let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x1234))
         .manufacturer("alnyan")
         .product("card-o-matic")
         .serial_number("616c6e79616e01")
-        .device_class(USB_CLASS_CDC)
         .build();
 
     usb_dev.force_reset().ok();
@@ -97,7 +105,7 @@ fn main() -> ! {
     // Don't care about the result: will have a chance to reinit
     sd.init().ok();
 
-    let mut driver = Driver::new(sd, usb_dev, usb_serial);
+    let mut driver = MscDriver::new(sd, usb_dev, usb_msc);
 
     loop {
         driver.poll();
@@ -0,0 +1,510 @@
+use core::ops::Deref;
+use stm32f1xx_hal::pac::spi1::RegisterBlock as SpiRegs;
+use usb_device::class_prelude::*;
+use usb_device::control;
+use usb_device::prelude::*;
+use usb_device::Result as UsbResult;
+
+use crate::fat::{DirEntry, Fat};
+use crate::sd::{SdCardStatus, SpiSdCard};
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+
+const SCSI_TEST_UNIT_READY: u8 = 0x00;
+const SCSI_REQUEST_SENSE: u8 = 0x03;
+const SCSI_INQUIRY: u8 = 0x12;
+const SCSI_MODE_SENSE_6: u8 = 0x1A;
+const SCSI_READ_CAPACITY_10: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+const SCSI_WRITE_10: u8 = 0x2A;
+const SCSI_PREVENT_ALLOW: u8 = 0x1E;
+
+// Vendor-specific opcodes (0xC0-0xFF is vendor-unique per SPC) exposing the
+// FAT layer's directory listing/file reading over the BOT transport, since
+// `driver.rs`'s CDC protocol is no longer the active driver and BOT has no
+// room for a second interface.
+const VENDOR_LIST_DIR: u8 = 0xC0;
+const VENDOR_READ_FILE: u8 = 0xC1;
+
+// One byte of count plus up to this many 16-byte (name, is_dir, size) records.
+const MSC_MAX_DIR_ENTRIES: usize = 15;
+// VENDOR_READ_FILE's CDB carries the file name in cb[1..16]: 15 bytes.
+const MSC_MAX_NAME_LEN: usize = 15;
+
+/// SCSI sense key / ASC / ASCQ, reported to the host via REQUEST SENSE after
+/// any command completes with CSW status `Failed`.
+#[derive(Copy, Clone)]
+struct Sense {
+    key: u8,
+    asc: u8,
+    ascq: u8,
+}
+
+const SENSE_NONE: Sense = Sense { key: 0x00, asc: 0x00, ascq: 0x00 };
+// NOT READY / MEDIUM NOT PRESENT
+const SENSE_NOT_READY: Sense = Sense { key: 0x02, asc: 0x3A, ascq: 0x00 };
+// MEDIUM ERROR / UNRECOVERED READ ERROR
+const SENSE_READ_ERROR: Sense = Sense { key: 0x03, asc: 0x11, ascq: 0x00 };
+// MEDIUM ERROR / WRITE ERROR
+const SENSE_WRITE_ERROR: Sense = Sense { key: 0x03, asc: 0x0C, ascq: 0x00 };
+// ILLEGAL REQUEST / INVALID COMMAND OPERATION CODE
+const SENSE_ILLEGAL_REQUEST: Sense = Sense { key: 0x05, asc: 0x20, ascq: 0x00 };
+
+// Bulk-Only Transport class-specific control requests (USB MSC BOT 1.0)
+const REQ_MASS_STORAGE_RESET: u8 = 0xFF;
+const REQ_GET_MAX_LUN: u8 = 0xFE;
+
+const BLOCK_SIZE: usize = 512;
+
+/// Cap on consecutive `WouldBlock` retries in a single bulk transfer call,
+/// so a stalled or unresponsive host can't spin the driver forever.
+const BULK_RETRY_LIMIT: u32 = 100_000;
+
+static mut BLOCK_BUF: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
+
+#[derive(Copy, Clone, PartialEq)]
+#[repr(u8)]
+enum CswStatus {
+    Passed = 0x00,
+    Failed = 0x01,
+}
+
+struct Cbw {
+    tag: u32,
+    data_len: u32,
+    cb: [u8; 16],
+}
+
+impl Cbw {
+    fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < 31 || u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) != CBW_SIGNATURE {
+            return None;
+        }
+
+        let tag = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        let data_len = u32::from_le_bytes([buf[8], buf[9], buf[10], buf[11]]);
+        let mut cb = [0u8; 16];
+        cb.copy_from_slice(&buf[15..31]);
+
+        Some(Self { tag, data_len, cb })
+    }
+}
+
+fn write_csw(buf: &mut [u8; 13], tag: u32, residue: u32, status: CswStatus) {
+    buf[0..4].copy_from_slice(&CSW_SIGNATURE.to_le_bytes());
+    buf[4..8].copy_from_slice(&tag.to_le_bytes());
+    buf[8..12].copy_from_slice(&residue.to_le_bytes());
+    buf[12] = status as u8;
+}
+
+/// The bulk-only transport endpoints and interface descriptor for USB Mass
+/// Storage Class. Unlike `usbd_serial::SerialPort`, there is no off-the-shelf
+/// `UsbClass` for MSC, so this implements just enough of it (the interface
+/// descriptor plus the BOT reset/get-max-lun control requests) to satisfy a
+/// host's class driver; the SCSI command loop itself lives in `MscDriver`.
+pub struct MscClass<'a, B: UsbBus> {
+    iface: InterfaceNumber,
+    ep_out: EndpointOut<'a, B>,
+    ep_in: EndpointIn<'a, B>,
+}
+
+impl<'a, B: UsbBus> MscClass<'a, B> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        Self {
+            iface: alloc.interface(),
+            ep_out: alloc.bulk(64),
+            ep_in: alloc.bulk(64),
+        }
+    }
+
+    /// Makes one non-blocking attempt to read whatever the host has already
+    /// sent, returning 0 on `WouldBlock` instead of spinning. Used to
+    /// accumulate a CBW across `poll()` calls so a wake with no bulk-OUT
+    /// data (control traffic, an IN completion, SOF) doesn't strand EP0.
+    fn try_read(&mut self, buf: &mut [u8]) -> usize {
+        match self.ep_out.read(buf) {
+            Ok(count) => count,
+            Err(_) => 0,
+        }
+    }
+
+    fn read_bulk(&mut self, buf: &mut [u8]) -> usize {
+        let mut total = 0;
+        let mut stalls = 0;
+        while total < buf.len() && stalls < BULK_RETRY_LIMIT {
+            match self.ep_out.read(&mut buf[total..]) {
+                Ok(count) => {
+                    total += count;
+                    stalls = 0;
+                    if count < 64 {
+                        break;
+                    }
+                }
+                Err(UsbError::WouldBlock) => stalls += 1,
+                Err(_) => break,
+            }
+        }
+        total
+    }
+
+    fn write_bulk(&mut self, data: &[u8]) {
+        for chunk in data.chunks(64) {
+            let mut stalls = 0;
+            while self.ep_in.write(chunk).is_err() && stalls < BULK_RETRY_LIMIT {
+                stalls += 1;
+            }
+        }
+    }
+
+    /// Halts the bulk-IN endpoint, signalling the host that a data-phase
+    /// error cut a transfer short instead of silently padding it out.
+    fn stall_in(&mut self) {
+        self.ep_in.stall();
+    }
+}
+
+impl<'a, B: UsbBus> UsbClass<B> for MscClass<'a, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> UsbResult<()> {
+        // Mass Storage, SCSI transparent command set, Bulk-Only Transport
+        writer.interface(self.iface, 0x08, 0x06, 0x50)?;
+        writer.endpoint(&self.ep_out)?;
+        writer.endpoint(&self.ep_in)?;
+        Ok(())
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        if req.request_type == control::RequestType::Class
+            && req.recipient == control::Recipient::Interface
+            && req.index == u8::from(self.iface) as u16
+            && req.request == REQ_GET_MAX_LUN
+        {
+            xfer.accept_with(&[0]).ok();
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+        if req.request_type == control::RequestType::Class
+            && req.recipient == control::Recipient::Interface
+            && req.index == u8::from(self.iface) as u16
+            && req.request == REQ_MASS_STORAGE_RESET
+        {
+            xfer.accept().ok();
+        }
+    }
+}
+
+pub struct MscDriver<'a, SPI: Deref<Target = SpiRegs>, REMAP, PINS, B: UsbBus> {
+    sd: SpiSdCard<SPI, REMAP, PINS>,
+
+    msc: MscClass<'a, B>,
+    dev: UsbDevice<'a, B>,
+    card_was_present: bool,
+    sense: Sense,
+    // Accumulates a CBW across poll() calls; see `poll_cbw`.
+    cbw_buf: [u8; 31],
+    cbw_len: usize,
+}
+
+impl<'a, SPI: Deref<Target = SpiRegs>, R, P, B: UsbBus> MscDriver<'a, SPI, R, P, B> {
+    pub const fn new(sd: SpiSdCard<SPI, R, P>, dev: UsbDevice<'a, B>, msc: MscClass<'a, B>) -> Self {
+        Self {
+            sd,
+            msc,
+            dev,
+            card_was_present: true,
+            sense: SENSE_NONE,
+            cbw_buf: [0u8; 31],
+            cbw_len: 0,
+        }
+    }
+
+    fn poll_card_detect(&mut self) {
+        let present = self.sd.card_present();
+        if present == self.card_was_present {
+            return;
+        }
+        self.card_was_present = present;
+
+        if present {
+            self.sd.init().ok();
+        } else {
+            self.sd.mark_absent();
+        }
+    }
+
+    fn complete(&mut self, tag: u32, residue: u32, status: CswStatus) {
+        let mut csw = [0u8; 13];
+        write_csw(&mut csw, tag, residue, status);
+        self.msc.write_bulk(&csw);
+    }
+
+    fn handle_test_unit_ready(&mut self, cbw: &Cbw) {
+        let status = match self.sd.status() {
+            SdCardStatus::Ready(_) => CswStatus::Passed,
+            _ => {
+                self.sense = SENSE_NOT_READY;
+                CswStatus::Failed
+            }
+        };
+        self.complete(cbw.tag, cbw.data_len, status);
+    }
+
+    fn handle_request_sense(&mut self, cbw: &Cbw) {
+        let mut resp = [0u8; 18];
+        resp[0] = 0x70; // Current errors, fixed format
+        resp[2] = self.sense.key;
+        resp[7] = 10; // Additional sense length
+        resp[12] = self.sense.asc;
+        resp[13] = self.sense.ascq;
+
+        let len = (cbw.data_len as usize).min(resp.len());
+        self.msc.write_bulk(&resp[..len]);
+        self.complete(cbw.tag, cbw.data_len - len as u32, CswStatus::Passed);
+
+        self.sense = SENSE_NONE;
+    }
+
+    fn handle_mode_sense6(&mut self, cbw: &Cbw) {
+        // No mode pages or block descriptors supported: a bare mode
+        // parameter header (not write-protected) is enough for hosts that
+        // just probe it before mounting.
+        let resp = [3u8, 0, 0, 0];
+        let len = (cbw.data_len as usize).min(resp.len());
+        self.msc.write_bulk(&resp[..len]);
+        self.complete(cbw.tag, cbw.data_len - len as u32, CswStatus::Passed);
+    }
+
+    fn handle_prevent_allow(&mut self, cbw: &Cbw) {
+        // Medium removal can't actually be prevented; acknowledge and move on.
+        self.complete(cbw.tag, cbw.data_len, CswStatus::Passed);
+    }
+
+    fn handle_inquiry(&mut self, cbw: &Cbw) {
+        let mut resp = [0u8; 36];
+        resp[0] = 0x00; // Direct access block device
+        resp[1] = 0x80; // Removable medium
+        resp[3] = 0x01; // Response data format
+        resp[4] = 31; // Additional length
+        resp[8..16].copy_from_slice(b"alnyan  ");
+        resp[16..32].copy_from_slice(b"cdc-card-reader ");
+        resp[32..36].copy_from_slice(b"1.0 ");
+
+        let len = (cbw.data_len as usize).min(resp.len());
+        self.msc.write_bulk(&resp[..len]);
+        self.complete(cbw.tag, cbw.data_len - len as u32, CswStatus::Passed);
+    }
+
+    fn handle_read_capacity(&mut self, cbw: &Cbw) {
+        match self.sd.status() {
+            SdCardStatus::Ready(cap) => {
+                let mut resp = [0u8; 8];
+                resp[0..4].copy_from_slice(&(cap.saturating_sub(1) as u32).to_be_bytes());
+                resp[4..8].copy_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+
+                let len = (cbw.data_len as usize).min(resp.len());
+                self.msc.write_bulk(&resp[..len]);
+                self.complete(cbw.tag, cbw.data_len - len as u32, CswStatus::Passed);
+            }
+            _ => {
+                self.sense = SENSE_NOT_READY;
+                self.complete(cbw.tag, cbw.data_len, CswStatus::Failed);
+            }
+        }
+    }
+
+    fn handle_read10(&mut self, cbw: &Cbw) {
+        let lba = u32::from_be_bytes([cbw.cb[2], cbw.cb[3], cbw.cb[4], cbw.cb[5]]);
+        let count = u16::from_be_bytes([cbw.cb[7], cbw.cb[8]]);
+
+        let mut done: u32 = 0;
+        let mut status = CswStatus::Passed;
+
+        for i in 0..count {
+            let buf = unsafe { &mut BLOCK_BUF };
+            match self.sd.read_sector(lba + i as u32, buf) {
+                Ok(_) => {
+                    self.msc.write_bulk(buf);
+                    done += BLOCK_SIZE as u32;
+                }
+                Err(_) => {
+                    self.sense = SENSE_READ_ERROR;
+                    status = CswStatus::Failed;
+                    self.msc.stall_in();
+                    break;
+                }
+            }
+        }
+
+        self.complete(cbw.tag, cbw.data_len.saturating_sub(done), status);
+    }
+
+    fn handle_write10(&mut self, cbw: &Cbw) {
+        let lba = u32::from_be_bytes([cbw.cb[2], cbw.cb[3], cbw.cb[4], cbw.cb[5]]);
+        let count = u16::from_be_bytes([cbw.cb[7], cbw.cb[8]]);
+
+        let mut done: u32 = 0;
+        let mut status = CswStatus::Passed;
+
+        for i in 0..count {
+            let buf = unsafe { &mut BLOCK_BUF };
+            self.msc.read_bulk(buf);
+
+            if status == CswStatus::Passed {
+                match self.sd.write_sector(lba + i as u32, buf) {
+                    Ok(_) => done += BLOCK_SIZE as u32,
+                    Err(_) => {
+                        self.sense = SENSE_WRITE_ERROR;
+                        status = CswStatus::Failed;
+                    }
+                }
+            }
+        }
+
+        self.complete(cbw.tag, cbw.data_len.saturating_sub(done), status);
+    }
+
+    fn handle_list_dir(&mut self, cbw: &Cbw) {
+        let mut fat = match Fat::mount(&mut self.sd) {
+            Ok(fat) => fat,
+            Err(_) => {
+                self.sense = SENSE_NOT_READY;
+                self.complete(cbw.tag, cbw.data_len, CswStatus::Failed);
+                return;
+            }
+        };
+
+        let mut entries: [Option<DirEntry>; MSC_MAX_DIR_ENTRIES] = [None; MSC_MAX_DIR_ENTRIES];
+        let mut count = 0usize;
+        let res = fat.read_root_dir(|entry| {
+            if count < MSC_MAX_DIR_ENTRIES {
+                entries[count] = Some(*entry);
+                count += 1;
+            }
+        });
+
+        if res.is_err() {
+            self.sense = SENSE_READ_ERROR;
+            self.complete(cbw.tag, cbw.data_len, CswStatus::Failed);
+            return;
+        }
+
+        let mut resp = [0u8; 1 + MSC_MAX_DIR_ENTRIES * 16];
+        resp[0] = count as u8;
+        for (i, entry) in entries[..count].iter().filter_map(|e| e.as_ref()).enumerate() {
+            let off = 1 + i * 16;
+            resp[off..off + 11].copy_from_slice(&entry.name);
+            resp[off + 11] = entry.is_dir as u8;
+            resp[off + 12..off + 16].copy_from_slice(&entry.size.to_le_bytes());
+        }
+
+        let len = (cbw.data_len as usize).min(1 + count * 16);
+        self.msc.write_bulk(&resp[..len]);
+        self.complete(cbw.tag, cbw.data_len.saturating_sub(len as u32), CswStatus::Passed);
+    }
+
+    fn handle_read_file(&mut self, cbw: &Cbw) {
+        let name_len = cbw.cb[1..]
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(MSC_MAX_NAME_LEN);
+        let name = match core::str::from_utf8(&cbw.cb[1..1 + name_len]) {
+            Ok(name) => name,
+            Err(_) => {
+                self.sense = SENSE_ILLEGAL_REQUEST;
+                self.complete(cbw.tag, cbw.data_len, CswStatus::Failed);
+                return;
+            }
+        };
+
+        let mut fat = match Fat::mount(&mut self.sd) {
+            Ok(fat) => fat,
+            Err(_) => {
+                self.sense = SENSE_NOT_READY;
+                self.complete(cbw.tag, cbw.data_len, CswStatus::Failed);
+                return;
+            }
+        };
+
+        let entry = match fat.find(name) {
+            Ok(entry) => entry,
+            Err(_) => {
+                self.sense = SENSE_ILLEGAL_REQUEST;
+                self.complete(cbw.tag, cbw.data_len, CswStatus::Failed);
+                return;
+            }
+        };
+
+        let mut done = 0u32;
+        let mut status = CswStatus::Passed;
+        let mut buf = [0u8; 512];
+
+        while done < cbw.data_len && done < entry.size {
+            let n = match fat.read_file(&entry, done, &mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(_) => {
+                    self.sense = SENSE_READ_ERROR;
+                    status = CswStatus::Failed;
+                    self.msc.stall_in();
+                    break;
+                }
+            };
+
+            let n = n.min((cbw.data_len - done) as usize);
+            self.msc.write_bulk(&buf[..n]);
+            done += n as u32;
+        }
+
+        self.complete(cbw.tag, cbw.data_len.saturating_sub(done), status);
+    }
+
+    fn handle_command(&mut self, cbw: &Cbw) {
+        match cbw.cb[0] {
+            SCSI_TEST_UNIT_READY => self.handle_test_unit_ready(cbw),
+            SCSI_REQUEST_SENSE => self.handle_request_sense(cbw),
+            SCSI_INQUIRY => self.handle_inquiry(cbw),
+            SCSI_MODE_SENSE_6 => self.handle_mode_sense6(cbw),
+            SCSI_PREVENT_ALLOW => self.handle_prevent_allow(cbw),
+            SCSI_READ_CAPACITY_10 => self.handle_read_capacity(cbw),
+            SCSI_READ_10 => self.handle_read10(cbw),
+            SCSI_WRITE_10 => self.handle_write10(cbw),
+            VENDOR_LIST_DIR => self.handle_list_dir(cbw),
+            VENDOR_READ_FILE => self.handle_read_file(cbw),
+            _ => {
+                self.sense = SENSE_ILLEGAL_REQUEST;
+                self.complete(cbw.tag, cbw.data_len, CswStatus::Failed);
+            }
+        }
+    }
+
+    /// Tops up `cbw_buf` with whatever the host has sent so far, without
+    /// blocking: a `dev.poll()` wake doesn't imply ep_out has a packet
+    /// waiting (it can just as well be control traffic, an IN completion,
+    /// or SOF), so this must never spin waiting for one.
+    fn poll_cbw(&mut self) {
+        let received = self.msc.try_read(&mut self.cbw_buf[self.cbw_len..]);
+        self.cbw_len += received;
+
+        if self.cbw_len < self.cbw_buf.len() {
+            return;
+        }
+
+        if let Some(cbw) = Cbw::parse(&self.cbw_buf) {
+            self.handle_command(&cbw);
+        }
+        self.cbw_len = 0;
+    }
+
+    pub fn poll(&mut self) {
+        self.poll_card_detect();
+
+        if !self.dev.poll(&mut [&mut self.msc]) {
+            return;
+        }
+
+        self.poll_cbw();
+    }
+}
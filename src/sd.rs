@@ -1,14 +1,16 @@
 use core::ops::Deref;
 use cortex_m::asm::delay;
 use cortex_m::prelude::*;
-use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::digital::v2::{InputPin, OutputPin};
 use nb::block;
 use stm32f1xx_hal::{
     pac,
-    gpio::{gpiob, Output, PushPull},
+    gpio::{gpiob, Input, Output, PullUp, PushPull},
     spi::{Error as SpiError, Spi},
 };
 
+use crate::crc::{crc16_ccitt, crc7};
+
 #[derive(Copy, Clone)]
 pub enum SdCardStatus {
     Init,
@@ -16,18 +18,29 @@ pub enum SdCardStatus {
     Failed
 }
 
+#[derive(Copy, Clone)]
+enum AddressMode {
+    /// SDSC cards address data by byte offset, not block number.
+    ByteAddressed,
+    /// SDHC/SDXC cards address data directly by 512-byte block number.
+    BlockAddressed
+}
+
 #[derive(Debug)]
 pub enum SdCardError {
     SpiError,
     Timeout,
-    InvalidResponse
+    InvalidResponse,
+    CrcMismatch
 }
 
 pub struct SpiSdCard<SPI: Deref<Target = pac::spi1::RegisterBlock>, REMAP, PINS> {
     spi: Spi<SPI, REMAP, PINS, u8>,
     cs: gpiob::PB12<Output<PushPull>>,
+    cd: Option<gpiob::PB5<Input<PullUp>>>,
 
-    status: SdCardStatus
+    status: SdCardStatus,
+    addr_mode: AddressMode
 }
 
 impl From<SpiError> for SdCardError {
@@ -37,8 +50,39 @@ impl From<SpiError> for SdCardError {
 }
 
 impl<SPI: Deref<Target = pac::spi1::RegisterBlock>, REMAP, PINS> SpiSdCard<SPI, REMAP, PINS> {
-    pub const fn new(spi: Spi<SPI, REMAP, PINS, u8>, cs: gpiob::PB12<Output<PushPull>>) -> Self {
-        Self { spi, cs, status: SdCardStatus::Init }
+    pub const fn new(
+        spi: Spi<SPI, REMAP, PINS, u8>,
+        cs: gpiob::PB12<Output<PushPull>>,
+        cd: Option<gpiob::PB5<Input<PullUp>>>,
+    ) -> Self {
+        Self { spi, cs, cd, status: SdCardStatus::Init, addr_mode: AddressMode::ByteAddressed }
+    }
+
+    /// Reads the card-detect switch, if one was wired up in `new`. Sockets
+    /// with this switch ground the pin while a card is seated, so a pulled-up
+    /// input reading low means "present". Without a card-detect pin, a card
+    /// is always assumed present.
+    pub fn card_present(&self) -> bool {
+        match &self.cd {
+            Some(pin) => pin.is_low().unwrap_or(true),
+            None => true
+        }
+    }
+
+    /// Marks the card as absent after a card-detect transition, so the next
+    /// successful `init()` is what brings status back to `Ready`.
+    pub fn mark_absent(&mut self) {
+        self.status = SdCardStatus::Init;
+    }
+
+    /// Translates an LBA into the address argument expected by the card:
+    /// byte-addressed (SDSC) cards take a byte offset, block-addressed
+    /// (SDHC/SDXC) cards take the block number directly.
+    fn card_addr(&self, lba: u32) -> u32 {
+        match self.addr_mode {
+            AddressMode::ByteAddressed => lba.wrapping_mul(512),
+            AddressMode::BlockAddressed => lba
+        }
     }
 
     pub fn txrx(&mut self, w: u8) -> Result<u8, SdCardError> {
@@ -47,15 +91,20 @@ impl<SPI: Deref<Target = pac::spi1::RegisterBlock>, REMAP, PINS> SpiSdCard<SPI,
         Ok(res)
     }
 
-    pub fn send_cmd(&mut self, cmd: u8, crc: u8, arg: u32) -> Result<u8, SdCardError> {
-        self.txrx(cmd)?;
-
-        self.txrx(((arg >> 24) & 0xFF) as u8)?;
-        self.txrx(((arg >> 16) & 0xFF) as u8)?;
-        self.txrx(((arg >> 8) & 0xFF) as u8)?;
-        self.txrx((arg & 0xFF) as u8)?;
+    pub fn send_cmd(&mut self, cmd: u8, arg: u32) -> Result<u8, SdCardError> {
+        let frame = [
+            cmd,
+            ((arg >> 24) & 0xFF) as u8,
+            ((arg >> 16) & 0xFF) as u8,
+            ((arg >> 8) & 0xFF) as u8,
+            (arg & 0xFF) as u8,
+        ];
+
+        for &byte in &frame {
+            self.txrx(byte)?;
+        }
 
-        self.txrx(crc)?;
+        self.txrx(crc7(&frame))?;
 
         for _ in 0..10 {
             let tmp = self.txrx(0xFF)?;
@@ -72,7 +121,7 @@ impl<SPI: Deref<Target = pac::spi1::RegisterBlock>, REMAP, PINS> SpiSdCard<SPI,
         self.cs.set_low().ok();
         delay(10000);
 
-        self.send_cmd(0x40 + 17, 0x00, addr)?;
+        self.send_cmd(0x40 + 17, self.card_addr(addr))?;
 
         for _ in 0..10 {
             tmp = self.txrx(0xFF)?;
@@ -85,10 +134,16 @@ impl<SPI: Deref<Target = pac::spi1::RegisterBlock>, REMAP, PINS> SpiSdCard<SPI,
             for byte in buf.iter_mut() {
                 *byte = self.txrx(0xFF)?;
             }
-            self.txrx(0xFF)?;
-            self.txrx(0xFF)?;
+            let crc_hi = self.txrx(0xFF)?;
+            let crc_lo = self.txrx(0xFF)?;
             self.cs.set_high().ok();
             delay(10000);
+
+            let crc = ((crc_hi as u16) << 8) | crc_lo as u16;
+            if crc != crc16_ccitt(buf) {
+                return Err(SdCardError::CrcMismatch);
+            }
+
             Ok(())
         } else {
             self.cs.set_high().ok();
@@ -103,7 +158,7 @@ impl<SPI: Deref<Target = pac::spi1::RegisterBlock>, REMAP, PINS> SpiSdCard<SPI,
         self.cs.set_low().ok();
         delay(10000);
 
-        self.send_cmd(0x40 + 24, 0x00, addr)?;
+        self.send_cmd(0x40 + 24, self.card_addr(addr))?;
 
         // Wait for SD to become ready
         for _ in 0..10 {
@@ -121,9 +176,9 @@ impl<SPI: Deref<Target = pac::spi1::RegisterBlock>, REMAP, PINS> SpiSdCard<SPI,
                 self.txrx(byte)?;
             }
 
-            // Discard CRC
-            self.txrx(0xFF)?;
-            self.txrx(0xFF)?;
+            let crc = crc16_ccitt(buf);
+            self.txrx((crc >> 8) as u8)?;
+            self.txrx((crc & 0xFF) as u8)?;
 
             for _ in 0..64 {
                 tmp = self.txrx(0xFF)?;
@@ -147,6 +202,105 @@ impl<SPI: Deref<Target = pac::spi1::RegisterBlock>, REMAP, PINS> SpiSdCard<SPI,
         }
     }
 
+    pub fn read_sectors(&mut self, addr: u32, count: usize, buf: &mut [u8]) -> Result<(), SdCardError> {
+        self.cs.set_low().ok();
+        delay(10000);
+
+        let res = self.send_cmd(0x40 + 18, self.card_addr(addr)).and_then(|_| {
+            for i in 0..count {
+                self.rx_data_block(&mut buf[i * 512..(i + 1) * 512])?;
+            }
+            Ok(())
+        });
+
+        // STOP_TRANSMISSION
+        self.send_cmd(0x40 + 12, 0).ok();
+        if let Err(e) = self.txrx(0xFF) {
+            self.cs.set_high().ok();
+            delay(10000);
+            return Err(e);
+        }
+
+        // Wait for the card to leave the busy state
+        for _ in 0..64 {
+            match self.txrx(0xFF) {
+                Ok(0) => {}
+                Ok(_) => break,
+                Err(e) => {
+                    self.cs.set_high().ok();
+                    delay(10000);
+                    return Err(e);
+                }
+            }
+            delay(100);
+        }
+
+        self.cs.set_high().ok();
+        delay(10000);
+
+        res
+    }
+
+    pub fn write_sectors(&mut self, addr: u32, buf: &[u8]) -> Result<(), SdCardError> {
+        let count = buf.len() / 512;
+        let mut tmp = 0u8;
+        self.cs.set_low().ok();
+        delay(10000);
+
+        self.send_cmd(0x40 + 25, self.card_addr(addr))?;
+
+        let mut res = Ok(());
+        'blocks: for i in 0..count {
+            // Wait for SD to become ready
+            for _ in 0..10 {
+                tmp = self.txrx(0xFF)?;
+                if tmp != 0xFF {
+                    break;
+                }
+            }
+
+            delay(10000);
+            // Multi-block write token
+            self.txrx(0xFC)?;
+
+            let block = &buf[i * 512..(i + 1) * 512];
+            for &byte in block {
+                self.txrx(byte)?;
+            }
+
+            let crc = crc16_ccitt(block);
+            self.txrx((crc >> 8) as u8)?;
+            self.txrx((crc & 0xFF) as u8)?;
+
+            for _ in 0..64 {
+                tmp = self.txrx(0xFF)?;
+                if (tmp & 0x1F) == 0x05 {
+                    break;
+                }
+            }
+
+            if (tmp & 0x1F) != 0x05 {
+                res = Err(SdCardError::InvalidResponse);
+                break 'blocks;
+            }
+
+            while self.txrx(0xFF)? == 0 {
+                delay(100);
+            }
+        }
+
+        // Stop-tran token
+        self.txrx(0xFD)?;
+        while self.txrx(0xFF)? == 0 {
+            delay(100);
+        }
+
+        self.cs.set_high().ok();
+        delay(10000);
+
+        res
+    }
+
     pub fn rx_data_block(&mut self, buf: &mut [u8]) -> Result<(), SdCardError> {
         let mut tmp = 0u8;
 
@@ -170,29 +324,43 @@ impl<SPI: Deref<Target = pac::spi1::RegisterBlock>, REMAP, PINS> SpiSdCard<SPI,
             *byte = self.txrx(0xFF)?;
         }
 
-        // Discard CRC
-        self.txrx(0xFF)?;
-        self.txrx(0xFF)?;
+        let crc_hi = self.txrx(0xFF)?;
+        let crc_lo = self.txrx(0xFF)?;
+        let crc = ((crc_hi as u16) << 8) | crc_lo as u16;
+        if crc != crc16_ccitt(buf) {
+            return Err(SdCardError::CrcMismatch);
+        }
 
         Ok(())
     }
 
     pub fn get_capacity(&mut self) -> Result<u64, SdCardError> {
         let size: u64;
-        let res = self.send_cmd(0x40 + 9, 0x00, 0x01)?;
+        let res = self.send_cmd(0x40 + 9, 0x01)?;
 
         if res == 0 {
             let mut csd = [0u8; 16];
             self.rx_data_block(&mut csd)?;
             if csd[0] >> 6 == 1 {
-                // SDCv2
+                // CSD v2 (SDHC/SDXC)
                 let csize = (csd[9] as u32) + ((csd[8] as u32) << 8) + 1;
                 size = (csize as u64) << 10;
             } else {
-                todo!()
+                // CSD v1 (SDSC)
+                let c_size = (((csd[6] & 0x03) as u32) << 10)
+                    | ((csd[7] as u32) << 2)
+                    | ((csd[8] >> 6) as u32);
+                let c_size_mult = ((csd[9] & 0x03) << 1) | (csd[10] >> 7);
+                let read_bl_len = csd[5] & 0x0F;
+
+                let capacity = ((c_size as u64) + 1)
+                    * (1u64 << (c_size_mult as u32 + 2))
+                    * (1u64 << (read_bl_len as u32));
+                // Report in 512-byte sectors, matching the CSD v2 branch above.
+                size = capacity >> 9;
             }
         } else {
-            todo!()
+            return Err(SdCardError::InvalidResponse);
         }
 
         Ok(size)
@@ -209,16 +377,19 @@ impl<SPI: Deref<Target = pac::spi1::RegisterBlock>, REMAP, PINS> SpiSdCard<SPI,
         }
 
         // CMD0: software reset
-        let res = self.send_cmd(0x40, 0x95, 0)?;
+        let res = self.send_cmd(0x40, 0)?;
         self.txrx(0xFF)?;
         if res & 0x7F != 1 {
             return Err(SdCardError::InvalidResponse);
         }
         delay(100000);
 
-        // CMD8: set voltage to 2.7-3.3V
-        let res = self.send_cmd(0x40 + 8, 0x86, 0x1AA)?;
-        if res == 0x01 {
+        // CMD8: set voltage to 2.7-3.3V. Only SD v2+ cards understand this;
+        // genuine v1.x cards answer with the illegal-command bit set
+        // (R1 = 0x05), which means "this is a v1 card", not a failure.
+        let res = self.send_cmd(0x40 + 8, 0x1AA)?;
+        let is_v2 = res == 0x01;
+        if is_v2 {
             let mut buf = [0u8; 4];
             for byte in buf.iter_mut() {
                 *byte = self.txrx(0xFF)?;
@@ -227,23 +398,50 @@ impl<SPI: Deref<Target = pac::spi1::RegisterBlock>, REMAP, PINS> SpiSdCard<SPI,
             // TODO validate that result is 0x1AA
         }
         self.txrx(0xFF)?;
-        if res != 0x01 {
+        if !is_v2 && res & 0x04 == 0 {
             return Err(SdCardError::InvalidResponse);
         }
         delay(100000);
 
+        // CMD59: enable card-side CRC checking
+        self.send_cmd(0x40 + 59, 0x01)?;
+        delay(100000);
+
+        // v1.x cards don't support high-capacity (HCS) addressing, so leave
+        // the bit unset for them; they're always byte-addressed.
+        let acmd41_arg = if is_v2 { 0x40000000 } else { 0x00000000 };
+
         // ACMD41: init sd card
-        self.send_cmd(0x40 + 55, 0x00, 0x00)?;
-        self.send_cmd(0x40 + 41, 0x00, 0x40000000)?;
+        self.send_cmd(0x40 + 55, 0x00)?;
+        self.send_cmd(0x40 + 41, acmd41_arg)?;
         delay(1000000);
 
         // Try ACMD1 until R1 == 0
         for _ in 0..10 {
-            self.send_cmd(0x40 + 55, 0x00, 0x0)?;
-            let res = self.send_cmd(0x40 + 41, 0x00, 0x40000000)?;
+            self.send_cmd(0x40 + 55, 0x0)?;
+            let res = self.send_cmd(0x40 + 41, acmd41_arg)?;
             delay(100000);
 
             if res == 0x00 {
+                if is_v2 {
+                    // CMD58: read OCR to find out whether the card uses byte
+                    // or block addressing (CCS bit, OCR bit 30)
+                    let res = self.send_cmd(0x40 + 58, 0)?;
+                    let mut ocr = [0u8; 4];
+                    for byte in ocr.iter_mut() {
+                        *byte = self.txrx(0xFF)?;
+                    }
+                    if res == 0x00 {
+                        self.addr_mode = if ocr[0] & 0x40 != 0 {
+                            AddressMode::BlockAddressed
+                        } else {
+                            AddressMode::ByteAddressed
+                        };
+                    }
+                } else {
+                    self.addr_mode = AddressMode::ByteAddressed;
+                }
+
                 // Identify card
                 return self.get_capacity();
             }